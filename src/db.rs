@@ -1,52 +1,281 @@
+use std::collections::HashMap;
 use std::fs::{create_dir, File, OpenOptions};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use std::io::prelude::*;
 use std::io::{self, BufWriter, SeekFrom};
 
-use bincode::{deserialize, serialize_into};
 use compress::{entropy::ari, rle};
-use serde::{Deserialize, Serialize};
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+use crate::index::{IndexFile, INDEX_FILE};
 
 pub const MASTER_DB: &'static str = "sources.qsdb";
 pub const DICTIONARY: &'static str = "dictionary";
 pub const DICTIONARY0: &'static str = "0.qsdd";
+pub const JOURNAL: &'static str = "commit.log";
 pub const BYTES_HEDAER: usize = 11;
-pub const BYTES_BLOCK: usize = 16;
+pub const BYTES_BLOCK: usize = 115;
 pub const BYTES_DICTIONARY_HEADER: usize = 8;
-pub const BYTES_DICTIONARY_BLOCK: usize = 16;
+pub const BYTES_DICTIONARY_BLOCK: usize = 17;
+pub const BYTES_JOURNAL_ENTRY: usize = 48;
+// Staging/backup names `compact` swaps the live dictionary, sources and
+// index files through. `COMPACT_JOURNAL`'s presence marks that all three
+// staged files are fully built and synced and the swap below is committed
+// to -- see `DBFile::recover_compaction`.
+pub const COMPACT_JOURNAL: &'static str = "compact.log";
+const DICT_STAGED: &'static str = "dictionary.compact_new";
+const DICT_BACKUP: &'static str = "dictionary.compact_old";
+const DB_STAGED: &'static str = "sources.qsdb.compact";
+const DB_BACKUP: &'static str = "sources.qsdb.compact_old";
+const INDEX_STAGED: &'static str = "index.qsxi.compact";
+const INDEX_BACKUP: &'static str = "index.qsxi.compact_old";
 pub const QSDB_REVERSION: u16 = 1;
-pub const DEFAULT_EXP: u8 = 4;
+// `dict_chain`'s descent reads dictionary entry row `current / pivot` from
+// the parent file expecting it to already exist, but rows are appended in
+// push order rather than indexed by that bucket number -- so any
+// `divisor_exp` greater than zero tries to read a row that was never
+// written for most `nth` values and fails with `UnexpectedEof`. Only
+// `divisor_exp == 0` (pivot 1) is safe today, since `current % 1` is always
+// 0 and the descent returns at the root without touching a child file.
+// This flattens the "segment tree" into a single linear dictionary file
+// until the bucket-indexing mismatch above is fixed.
+pub const DEFAULT_EXP: u8 = 0;
 pub const DEFAULT_HEADER: Header = Header {
     reversion: QSDB_REVERSION,
     divisor_exp: DEFAULT_EXP,
     len: 0,
 };
 
-#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+/// Reads a fixed-width on-disk record as explicit big-endian fields, so the
+/// byte layout is stable and documented instead of depending on a
+/// serialization crate's internal representation.
+pub trait FromReader: Sized {
+    fn from_reader<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+/// Writes a fixed-width on-disk record as explicit big-endian fields. See
+/// `FromReader`.
+pub trait ToWriter {
+    fn to_writer<W: Write>(&self, w: &mut W) -> io::Result<()>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Header {
     reversion: u16,
     divisor_exp: u8,
     len: u64,
 }
 
+impl FromReader for Header {
+    fn from_reader<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut reversion_buf = [0u8; 2];
+        r.read_exact(&mut reversion_buf)?;
+        let mut divisor_exp_buf = [0u8; 1];
+        r.read_exact(&mut divisor_exp_buf)?;
+        let mut len_buf = [0u8; 8];
+        r.read_exact(&mut len_buf)?;
+        Ok(Header {
+            reversion: u16::from_be_bytes(reversion_buf),
+            divisor_exp: divisor_exp_buf[0],
+            len: u64::from_be_bytes(len_buf),
+        })
+    }
+}
+
+impl ToWriter for Header {
+    fn to_writer<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.reversion.to_be_bytes())?;
+        w.write_all(&[self.divisor_exp])?;
+        w.write_all(&self.len.to_be_bytes())?;
+        Ok(())
+    }
+}
+
 // Dynamic allocation
-#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Block {
     nth: u64,
     len: u64,
+    codec: u8,
+    // `signed` is 0 unless this source was pushed with a signing key
+    // configured, in which case `signature`/`public_key` hold a compact
+    // secp256k1 ECDSA signature and compressed public key over it;
+    // otherwise both are zeroed.
+    signed: u8,
+    signature: [u8; 64],
+    public_key: [u8; 33],
     // Other field is code: Vec<u8>
 }
 
-#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+impl FromReader for Block {
+    fn from_reader<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut nth_buf = [0u8; 8];
+        r.read_exact(&mut nth_buf)?;
+        let mut len_buf = [0u8; 8];
+        r.read_exact(&mut len_buf)?;
+        let mut codec_buf = [0u8; 1];
+        r.read_exact(&mut codec_buf)?;
+        let mut signed_buf = [0u8; 1];
+        r.read_exact(&mut signed_buf)?;
+        let mut signature = [0u8; 64];
+        r.read_exact(&mut signature)?;
+        let mut public_key = [0u8; 33];
+        r.read_exact(&mut public_key)?;
+        Ok(Block {
+            nth: u64::from_be_bytes(nth_buf),
+            len: u64::from_be_bytes(len_buf),
+            codec: codec_buf[0],
+            signed: signed_buf[0],
+            signature,
+            public_key,
+        })
+    }
+}
+
+impl ToWriter for Block {
+    fn to_writer<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.nth.to_be_bytes())?;
+        w.write_all(&self.len.to_be_bytes())?;
+        w.write_all(&[self.codec])?;
+        w.write_all(&[self.signed])?;
+        w.write_all(&self.signature)?;
+        w.write_all(&self.public_key)?;
+        Ok(())
+    }
+}
+
+impl Block {
+    /// The insertion ordinal (1-based) this block was stored under.
+    pub fn nth(&self) -> u64 {
+        self.nth
+    }
+}
+
+/// Identifies how a `Block`'s body was encoded, so the read path knows
+/// exactly which decoder chain to run rather than assuming every body was
+/// (or wasn't) compressed the same way.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Rle,
+    Arithmetic,
+    RleArithmetic,
+}
+
+impl Codec {
+    pub const TAG_NONE: u8 = 0;
+    pub const TAG_RLE: u8 = 1;
+    pub const TAG_ARITHMETIC: u8 = 2;
+    pub const TAG_RLE_ARITHMETIC: u8 = 3;
+
+    pub fn tag(self) -> u8 {
+        match self {
+            Codec::None => Self::TAG_NONE,
+            Codec::Rle => Self::TAG_RLE,
+            Codec::Arithmetic => Self::TAG_ARITHMETIC,
+            Codec::RleArithmetic => Self::TAG_RLE_ARITHMETIC,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            Self::TAG_NONE => Ok(Codec::None),
+            Self::TAG_RLE => Ok(Codec::Rle),
+            Self::TAG_ARITHMETIC => Ok(Codec::Arithmetic),
+            Self::TAG_RLE_ARITHMETIC => Ok(Codec::RleArithmetic),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown block codec tag {}", tag),
+            )),
+        }
+    }
+
+    fn encode(self, source: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::None => source.to_vec(),
+            Codec::Rle => {
+                let mut encoder_rle = rle::Encoder::new(Vec::new());
+                encoder_rle.write_all(source).unwrap();
+                let (buf_rle, _): (Vec<u8>, _) = encoder_rle.finish();
+                buf_rle
+            }
+            Codec::Arithmetic => {
+                let mut encoder_ari = ari::ByteEncoder::new(BufWriter::new(Vec::new()));
+                encoder_ari.write_all(source).unwrap();
+                let (buf_ari, _) = encoder_ari.finish();
+                buf_ari.into_inner().unwrap()
+            }
+            Codec::RleArithmetic => {
+                // Double encoding by arithmetic encoder and run-length encoder
+                let mut encoder_rle = rle::Encoder::new(Vec::new());
+                encoder_rle.write_all(source).unwrap();
+                let (buf_rle, _): (Vec<u8>, _) = encoder_rle.finish();
+                let mut encoder_ari = ari::ByteEncoder::new(BufWriter::new(Vec::new()));
+                encoder_ari.write_all(&buf_rle).unwrap();
+                let (buf_ari, _) = encoder_ari.finish();
+                buf_ari.into_inner().unwrap()
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct DictionaryHeader {
     len: u64,
 }
 
-#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+impl FromReader for DictionaryHeader {
+    fn from_reader<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut len_buf = [0u8; 8];
+        r.read_exact(&mut len_buf)?;
+        Ok(DictionaryHeader {
+            len: u64::from_be_bytes(len_buf),
+        })
+    }
+}
+
+impl ToWriter for DictionaryHeader {
+    fn to_writer<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.len.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct DictionaryBlock {
     nth: u64,
     offset: u64,
+    // 0 unless `delete` has tombstoned this entry; tombstoned entries are
+    // skipped by `locate`/`get` and dropped entirely by `compact`.
+    tombstone: u8,
+}
+
+impl FromReader for DictionaryBlock {
+    fn from_reader<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut nth_buf = [0u8; 8];
+        r.read_exact(&mut nth_buf)?;
+        let mut offset_buf = [0u8; 8];
+        r.read_exact(&mut offset_buf)?;
+        let mut tombstone_buf = [0u8; 1];
+        r.read_exact(&mut tombstone_buf)?;
+        Ok(DictionaryBlock {
+            nth: u64::from_be_bytes(nth_buf),
+            offset: u64::from_be_bytes(offset_buf),
+            tombstone: tombstone_buf[0],
+        })
+    }
+}
+
+impl ToWriter for DictionaryBlock {
+    fn to_writer<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.nth.to_be_bytes())?;
+        w.write_all(&self.offset.to_be_bytes())?;
+        w.write_all(&[self.tombstone])?;
+        Ok(())
+    }
 }
 
 pub enum Mode {
@@ -54,6 +283,61 @@ pub enum Mode {
     Modification,
 }
 
+/// Summary of what a `compact` pass did, so callers can decide whether
+/// vacuuming was worth it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub live: u64,
+    pub dead: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// Intent record for a single `push`, written to `commit.log` before any of
+/// the header, dictionary or body mutations it describes, and removed once
+/// all of them have landed. If `DBFile::open` finds a non-empty journal it
+/// means the process died mid-commit, and it replays or rolls back this
+/// entry before the database is handed back to the caller.
+#[derive(Clone, Debug)]
+struct JournalEntry {
+    nth: u64,
+    offset: u64,
+    body_len: u64,
+    header_len_before: u64,
+    dict_idx: u64,
+    dict_len_before: u64,
+}
+
+impl FromReader for JournalEntry {
+    fn from_reader<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut fields = [0u64; 6];
+        for field in fields.iter_mut() {
+            let mut buf = [0u8; 8];
+            r.read_exact(&mut buf)?;
+            *field = u64::from_be_bytes(buf);
+        }
+        Ok(JournalEntry {
+            nth: fields[0],
+            offset: fields[1],
+            body_len: fields[2],
+            header_len_before: fields[3],
+            dict_idx: fields[4],
+            dict_len_before: fields[5],
+        })
+    }
+}
+
+impl ToWriter for JournalEntry {
+    fn to_writer<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.nth.to_be_bytes())?;
+        w.write_all(&self.offset.to_be_bytes())?;
+        w.write_all(&self.body_len.to_be_bytes())?;
+        w.write_all(&self.header_len_before.to_be_bytes())?;
+        w.write_all(&self.dict_idx.to_be_bytes())?;
+        w.write_all(&self.dict_len_before.to_be_bytes())?;
+        Ok(())
+    }
+}
+
 /// # DBFile structure
 /// It manages source code(s) by segment tree
 /// - sources.qsdb
@@ -63,15 +347,26 @@ pub enum Mode {
 /// -- ...
 /// -- n.qsdd
 /// # Operation
-/// - push(source: Vec<u8>) : costs O(lgn)
+/// - push(source: Vec<u8>) : costs O(lgn) once the segment tree descent
+///   described at `DEFAULT_EXP` is fixed; currently O(1), since the default
+///   `divisor_exp` flattens the tree into one linear dictionary file that
+///   `push` only appends to.
 #[derive(Clone)]
 pub struct DBFile {
     source_db_root: PathBuf,
     header: Header,
+    // When set, every `push` is hashed and signed with this key, and the
+    // signature/public key are carried in the `Block`; this is the
+    // "optional integrity mode" toggle for tamper-evidence.
+    signing_key: Option<SecretKey>,
 }
 
 impl DBFile {
-    pub fn new(source_db_root: PathBuf, exp_wrapped: Option<u8>) -> io::Result<Self> {
+    pub fn new(
+        source_db_root: PathBuf,
+        exp_wrapped: Option<u8>,
+        signing_key: Option<SecretKey>,
+    ) -> io::Result<Self> {
         let mut header: Header = DEFAULT_HEADER;
         if let Some(exp) = exp_wrapped {
             header.divisor_exp = exp;
@@ -85,26 +380,196 @@ impl DBFile {
             DictionaryHeader { len: 0 },
             Mode::Create,
         )?;
+        IndexFile::create(source_db_root.clone())?;
 
         Ok(Self {
             source_db_root: source_db_root,
             header: header,
+            signing_key,
         })
     }
 
-    pub fn open(source_db_root: PathBuf) -> io::Result<Self> {
+    pub fn open(source_db_root: PathBuf, signing_key: Option<SecretKey>) -> io::Result<Self> {
+        Self::recover(source_db_root.clone())?;
         Ok(Self {
             source_db_root: source_db_root.clone(),
             header: Self::inner_read_header(source_db_root.clone())?,
+            signing_key,
         })
     }
 
+    /// Completes or rolls back a `push` that was interrupted mid-commit, by
+    /// inspecting the journal left behind in `commit.log`. A no-op when the
+    /// journal is absent or empty, which is the case after every clean
+    /// commit.
+    fn recover(source_db_root: PathBuf) -> io::Result<()> {
+        Self::recover_compaction(source_db_root.clone())?;
+
+        let journal_path = source_db_root.join(JOURNAL);
+        let mut journal_file = match File::open(&journal_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let journal_len = journal_file.metadata()?.len();
+        if journal_len == 0 {
+            drop(journal_file);
+            std::fs::remove_file(&journal_path)?;
+            return Ok(());
+        }
+        if journal_len != BYTES_JOURNAL_ENTRY as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "corrupt journal: expected {} bytes, found {}",
+                    BYTES_JOURNAL_ENTRY, journal_len
+                ),
+            ));
+        }
+        let entry = JournalEntry::from_reader(&mut journal_file)?;
+
+        let db_path = source_db_root.join(MASTER_DB);
+        let db_len = File::open(&db_path)?.metadata()?.len();
+        let committed = db_len >= entry.offset + (BYTES_BLOCK as u64) + entry.body_len;
+
+        let mut header = Self::inner_read_header(source_db_root.clone())?;
+        if committed {
+            // The body made it to disk; just make sure the header length
+            // agrees with it.
+            if header.len < entry.nth {
+                header.len = entry.nth;
+                Self::inner_write_header(source_db_root.clone(), header, Mode::Modification)?;
+            }
+        } else {
+            // The body never fully landed: discard the partial write and
+            // restore the header/dictionary to how they were before this
+            // push began.
+            let db_file = OpenOptions::new().write(true).open(&db_path)?;
+            db_file.set_len(entry.offset)?;
+            db_file.sync_all()?;
+
+            header.len = entry.header_len_before;
+            Self::inner_write_header(source_db_root.clone(), header, Mode::Modification)?;
+
+            let dict_path = source_db_root
+                .join(DICTIONARY)
+                .join(format!("{}.qsdd", entry.dict_idx));
+            let dict_file = OpenOptions::new().write(true).open(&dict_path)?;
+            dict_file.set_len(entry.dict_len_before)?;
+            dict_file.sync_all()?;
+            drop(dict_file);
+
+            // `push_dict` bumps and syncs the dictionary header's `len`
+            // before appending (and without syncing) the new entry, so the
+            // header can be ahead of what's actually on disk here. Recompute
+            // it from the truncated byte length rather than trusting
+            // whatever the header currently says, so a crash between those
+            // two writes doesn't leave `len` pointing past the last real
+            // entry.
+            let restored_dict_len = (entry.dict_len_before - BYTES_DICTIONARY_HEADER as u64)
+                / BYTES_DICTIONARY_BLOCK as u64;
+            Self::inner_write_dict_header(
+                entry.dict_idx,
+                source_db_root.clone(),
+                DictionaryHeader {
+                    len: restored_dict_len,
+                },
+                Mode::Modification,
+            )?;
+
+            let new_dict_path = source_db_root
+                .join(DICTIONARY)
+                .join(format!("{}.qsdd", entry.nth));
+            if new_dict_path.exists() {
+                std::fs::remove_file(&new_dict_path)?;
+            }
+        }
+
+        std::fs::remove_file(&journal_path)?;
+        Ok(())
+    }
+
+    fn write_journal(source_db_root: PathBuf, entry: &JournalEntry) -> io::Result<()> {
+        let mut journal_file = File::create(source_db_root.join(JOURNAL))?;
+        entry.to_writer(&mut journal_file)?;
+        journal_file.sync_all()?;
+        Ok(())
+    }
+
+    fn clear_journal(source_db_root: PathBuf) -> io::Result<()> {
+        std::fs::remove_file(source_db_root.join(JOURNAL))?;
+        Ok(())
+    }
+
+    /// Finishes an interrupted `compact()` pass, if one was caught mid-swap.
+    /// `compact()` only writes `COMPACT_JOURNAL` once the staged dictionary,
+    /// sources and index files are fully built and synced, so recovering
+    /// just means redoing whichever of the three renames below hadn't made
+    /// it to disk yet -- never rolling back, since the swaps that follow the
+    /// marker may already have moved the pre-compaction files aside or
+    /// removed them.
+    fn recover_compaction(source_db_root: PathBuf) -> io::Result<()> {
+        let marker = source_db_root.join(COMPACT_JOURNAL);
+        if !marker.exists() {
+            return Ok(());
+        }
+
+        Self::swap_in_dir(
+            &source_db_root.join(DICTIONARY),
+            &source_db_root.join(DICT_STAGED),
+            &source_db_root.join(DICT_BACKUP),
+        )?;
+        Self::swap_in_file(
+            &source_db_root.join(MASTER_DB),
+            &source_db_root.join(DB_STAGED),
+            &source_db_root.join(DB_BACKUP),
+        )?;
+        Self::swap_in_file(
+            &source_db_root.join(INDEX_FILE),
+            &source_db_root.join(INDEX_STAGED),
+            &source_db_root.join(INDEX_BACKUP),
+        )?;
+
+        std::fs::remove_file(&marker)?;
+        Ok(())
+    }
+
+    /// Renames `staged` in over `live` (backing it up to `backup` first and
+    /// removing the backup once the swap lands), resuming correctly no
+    /// matter which of the three steps a prior crash left off at: each is
+    /// skipped if its effect is already on disk. `live` and `staged` are
+    /// themselves directories -- used to swap in the dictionary tree.
+    fn swap_in_dir(live: &Path, staged: &Path, backup: &Path) -> io::Result<()> {
+        if !backup.exists() && staged.exists() {
+            std::fs::rename(live, backup)?;
+        }
+        if staged.exists() {
+            std::fs::rename(staged, live)?;
+        }
+        if backup.exists() {
+            std::fs::remove_dir_all(backup)?;
+        }
+        Ok(())
+    }
+
+    /// Like `swap_in_dir`, but for a single file -- used to swap in
+    /// `sources.qsdb` and `index.qsxi`.
+    fn swap_in_file(live: &Path, staged: &Path, backup: &Path) -> io::Result<()> {
+        if !backup.exists() && staged.exists() {
+            std::fs::rename(live, backup)?;
+        }
+        if staged.exists() {
+            std::fs::rename(staged, live)?;
+        }
+        if backup.exists() {
+            std::fs::remove_file(backup)?;
+        }
+        Ok(())
+    }
+
     pub fn inner_read_header(source_db_root: PathBuf) -> io::Result<Header> {
         let mut db_file = File::open(source_db_root.join(MASTER_DB))?;
-        let mut header_buf: [u8; BYTES_HEDAER] = [0; BYTES_HEDAER];
-        db_file.read_exact(&mut header_buf)?;
-        let header: Header = deserialize(&header_buf).unwrap();
-        Ok(header)
+        Header::from_reader(&mut db_file)
     }
 
     pub fn inner_write_header(
@@ -119,7 +584,7 @@ impl DBFile {
             _ => File::create(source_db_root.join(MASTER_DB))?,
         };
         db_file.seek(SeekFrom::Start(0))?;
-        serialize_into(&mut db_file, &header).unwrap();
+        header.to_writer(&mut db_file)?;
         db_file.sync_all()?;
         Ok(())
     }
@@ -133,11 +598,8 @@ impl DBFile {
                 .join(DICTIONARY)
                 .join(format!("{}.qsdd", idx)),
         )?;
-        let mut dict_header_buf: [u8; BYTES_DICTIONARY_HEADER] = [0; BYTES_DICTIONARY_HEADER];
         dict_file.seek(SeekFrom::Start(0))?;
-        dict_file.read_exact(&mut dict_header_buf)?;
-        let dict_header: DictionaryHeader = deserialize(&dict_header_buf).unwrap();
-        Ok(dict_header)
+        DictionaryHeader::from_reader(&mut dict_file)
     }
 
     pub fn inner_write_dict_header(
@@ -159,7 +621,7 @@ impl DBFile {
             )?,
         };
         dict_file.seek(SeekFrom::Start(0))?;
-        serialize_into(&mut dict_file, &dict_header).unwrap();
+        dict_header.to_writer(&mut dict_file)?;
         dict_file.sync_all()?;
         Ok(())
     }
@@ -173,10 +635,7 @@ impl DBFile {
         dict_file.seek(SeekFrom::Start(
             (BYTES_DICTIONARY_HEADER as u64) + (BYTES_DICTIONARY_BLOCK as u64) * i,
         ))?;
-        let mut dict_block_buf: [u8; BYTES_DICTIONARY_BLOCK] = [0; BYTES_DICTIONARY_BLOCK];
-        dict_file.read_exact(&mut dict_block_buf)?;
-        let dict_block: DictionaryBlock = deserialize(&dict_block_buf).unwrap();
-        Ok(dict_block)
+        DictionaryBlock::from_reader(&mut dict_file)
     }
 
     pub fn header(&self) -> Header {
@@ -187,83 +646,641 @@ impl DBFile {
         self.source_db_root.clone()
     }
 
-    /// It costs O(lgn)
-    pub fn push(&mut self, source: &[u8], compress: bool) -> io::Result<()> {
-        self.header.len += 1;
+    /// Costs O(lgn) once the segment-tree descent is fixed (see
+    /// `DEFAULT_EXP`); currently O(1) under the default header, since that
+    /// descent is disabled and every entry is appended to one linear
+    /// dictionary file.
+    ///
+    /// The header bump, dictionary update and body write are journaled in
+    /// `commit.log` first, so a crash partway through leaves something
+    /// `DBFile::open` can finish or undo instead of a segment tree that
+    /// silently disagrees with the header length.
+    pub fn push(&mut self, source: &[u8], codec: Codec) -> io::Result<()> {
+        let nth = self.header.len + 1;
+        let offset = File::open(self.source_db_root.join(MASTER_DB))?
+            .metadata()?
+            .len();
+
+        let dict_idx = self.dict_chain(nth)?;
+        let dict_len_before = File::open(
+            self.source_db_root
+                .join(DICTIONARY)
+                .join(format!("{}.qsdd", dict_idx)),
+        )?
+        .metadata()?
+        .len();
+
+        let body: Vec<u8> = codec.encode(source);
+
+        Self::write_journal(
+            self.source_db_root.clone(),
+            &JournalEntry {
+                nth,
+                offset,
+                body_len: body.len() as u64,
+                header_len_before: self.header.len,
+                dict_idx,
+                dict_len_before,
+            },
+        )?;
+
+        self.header.len = nth;
         Self::inner_write_header(self.source_db_root.clone(), self.header, Mode::Modification)?;
+        self.push_dict(dict_idx, nth, offset)?;
+
+        let (signed, signature, public_key) = match &self.signing_key {
+            Some(secret_key) => {
+                let secp = Secp256k1::signing_only();
+                let digest = Sha256::digest(source);
+                let message = Message::from_slice(&digest).unwrap();
+                let signature = secp.sign_ecdsa(&message, secret_key);
+                let public_key = PublicKey::from_secret_key(&secp, secret_key);
+                (1u8, signature.serialize_compact(), public_key.serialize())
+            }
+            None => (0u8, [0u8; 64], [0u8; 33]),
+        };
+
         let mut db_file = OpenOptions::new()
-            .read(true)
             .write(true)
             .open(self.source_db_root.join(MASTER_DB))?;
-        let metadata = db_file.metadata()?;
-        self.push_dict(self.header.len, metadata.len())?;
         let block: Block = Block {
-            nth: self.header.len,
+            nth,
             len: source.len() as u64,
+            codec: codec.tag(),
+            signed,
+            signature,
+            public_key,
         };
-        db_file.seek(SeekFrom::Start(metadata.len()))?;
-        serialize_into(&mut db_file, &block).ok();
-        db_file.seek(SeekFrom::Start(metadata.len() + (BYTES_BLOCK as u64)))?;
-        if compress {
-            // Double encoding by arithmetic encoder and run-length encoder
-            let mut encoder_rle = rle::Encoder::new(Vec::new());
-            encoder_rle.write_all(source).unwrap();
-            let (buf_rle, _): (Vec<u8>, _) = encoder_rle.finish();
-            let mut encoder_ari = ari::ByteEncoder::new(BufWriter::new(Vec::new()));
-            encoder_ari.write_all(&buf_rle).unwrap();
-            let (buf_ari, _) = encoder_ari.finish();
-            let inner = buf_ari.into_inner().unwrap();
-            db_file.write_all(&inner)?;
-        } else {
-            db_file.write_all(source)?;
-        }
+        db_file.seek(SeekFrom::Start(offset))?;
+        block.to_writer(&mut db_file)?;
+        db_file.seek(SeekFrom::Start(offset + (BYTES_BLOCK as u64)))?;
+        db_file.write_all(&body)?;
         db_file.sync_all()?;
+
+        Self::clear_journal(self.source_db_root.clone())?;
         Ok(())
     }
 
-    pub fn push_dict(&self, idx: u64, offset: u64) -> io::Result<()> {
-        // TODO: Reduce some overhead
-        // - too many file open(s) occur
-        //let dict_header = Self::inner_read_dict_header(0, self.source_db_root.clone())?;
-        //dict_header.len += 1;
+    /// Reads back the `nth`-th pushed source (0-indexed), walking the
+    /// segment-tree dictionary down to its offset -- O(lg n) once that
+    /// descent is fixed (see `DEFAULT_EXP`), currently O(n) under the
+    /// default header -- and reversing whichever codec chain `push` applied
+    /// to it, as recorded in the stored `Block`. `compressed` is ignored:
+    /// the codec tag on disk is what actually drives decoding.
+    pub fn get(&self, nth: u64, compressed: bool) -> io::Result<Vec<u8>> {
+        let _ = compressed;
+        let (_, source) = self.read_block(nth)?;
+        Ok(source)
+    }
+
+    /// Recomputes the SHA-256 digest of the `nth`-th source (0-indexed) and
+    /// checks it against the signature and public key stored in its
+    /// `Block`. Returns `Ok(false)` for sources pushed without a signing
+    /// key configured, rather than an error.
+    pub fn verify(&self, nth: u64) -> io::Result<bool> {
+        let (block, _) = self.read_block(nth)?;
+        if block.signed == 0 {
+            return Ok(false);
+        }
+        let public_key = PublicKey::from_slice(&block.public_key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.verify_public(nth, &public_key)
+    }
+
+    /// Like `verify`, but checks the signature against a caller-supplied
+    /// public key instead of trusting the one stored alongside the block --
+    /// use this to confirm a source was written by a *specific* known key.
+    pub fn verify_public(&self, nth: u64, public_key: &PublicKey) -> io::Result<bool> {
+        let (block, source) = self.read_block(nth)?;
+        if block.signed == 0 {
+            return Ok(false);
+        }
+        let signature = Signature::from_compact(&block.signature)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let secp = Secp256k1::verification_only();
+        let digest = Sha256::digest(&source);
+        let message = Message::from_slice(&digest).unwrap();
+        Ok(secp.verify_ecdsa(&message, &signature, public_key).is_ok())
+    }
+
+    /// Like `verify_public`, but identifies the expected signer by
+    /// `address` (see `DBFile::address`) rather than the full public key.
+    pub fn verify_address(&self, nth: u64, address: &[u8; 20]) -> io::Result<bool> {
+        let (block, _) = self.read_block(nth)?;
+        if block.signed == 0 {
+            return Ok(false);
+        }
+        let public_key = PublicKey::from_slice(&block.public_key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if Self::address(&public_key) != *address {
+            return Ok(false);
+        }
+        self.verify_public(nth, &public_key)
+    }
+
+    /// Derives a short address from a public key: the low 20 bytes of the
+    /// SHA-256 hash of its compressed encoding.
+    pub fn address(public_key: &PublicKey) -> [u8; 20] {
+        let digest = Sha256::digest(&public_key.serialize());
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&digest[12..32]);
+        address
+    }
+
+    /// Reads the `nth`-th pushed source (0-indexed) back, along with its
+    /// `Block` header: walks the segment-tree dictionary down to its offset
+    /// -- O(lg n) once that descent is fixed (see `DEFAULT_EXP`), currently
+    /// O(n) under the default header -- then reverses whichever codec chain
+    /// `push` applied to it, as recorded in the stored `Block`.
+    fn read_block(&self, nth: u64) -> io::Result<(Block, Vec<u8>)> {
+        let offset = self.locate(nth + 1)?;
+
+        let mut db_file = File::open(self.source_db_root.join(MASTER_DB))?;
+        db_file.seek(SeekFrom::Start(offset))?;
+        let block = Block::from_reader(&mut db_file)?;
+        let codec = Codec::from_tag(block.codec)?;
+
+        let mut source = vec![0u8; block.len as usize];
+        match codec {
+            Codec::None => db_file.read_exact(&mut source)?,
+            Codec::Rle => rle::Decoder::new(db_file).read_exact(&mut source)?,
+            Codec::Arithmetic => ari::ByteDecoder::new(db_file).read_exact(&mut source)?,
+            Codec::RleArithmetic => {
+                // Reverse the chain push applied: arithmetic decodes first,
+                // feeding its output into the RLE decoder.
+                let decoder_ari = ari::ByteDecoder::new(db_file);
+                rle::Decoder::new(decoder_ari).read_exact(&mut source)?
+            }
+        }
+        Ok((block, source))
+    }
+
+    /// Finds the dictionary entry for stored `nth` (1-based, as tracked by
+    /// `push`/`push_dict`) by descending the segment tree to its leaf
+    /// dictionary file and scanning that file's entries, returning the
+    /// entry's file index and position within it along with the entry
+    /// itself. Returns the entry regardless of its tombstone status.
+    fn find_entry(&self, nth: u64) -> io::Result<(u64, u64, DictionaryBlock)> {
+        let dict_idx = self.dict_chain(nth)?;
+        let dict_header = Self::inner_read_dict_header(dict_idx, self.source_db_root.clone())?;
+        for i in 0..dict_header.len {
+            let entry = self.dict_get(dict_idx, i)?;
+            if entry.nth == nth {
+                return Ok((dict_idx, i, entry));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no block found for nth {}", nth),
+        ))
+    }
+
+    /// Finds the offset of the dictionary entry for stored `nth` (1-based,
+    /// as tracked by `push`/`push_dict`). Tombstoned entries are treated as
+    /// absent, so `get` and friends skip deleted sources.
+    fn locate(&self, nth: u64) -> io::Result<u64> {
+        let (_, _, entry) = self.find_entry(nth)?;
+        if entry.tombstone != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("nth {} was deleted", nth),
+            ));
+        }
+        Ok(entry.offset)
+    }
+
+    /// Marks the `nth`-th pushed source (0-indexed) as deleted without
+    /// moving any data: the body stays in `MASTER_DB` and the dictionary
+    /// entry is flipped to a tombstone in place. `get`/`verify*` then treat
+    /// it as absent. Deleting an already-deleted `nth` is a no-op. Run
+    /// `compact` to actually reclaim the space tombstones leave behind.
+    pub fn delete(&mut self, nth: u64) -> io::Result<()> {
+        let (dict_idx, position, mut entry) = self.find_entry(nth + 1)?;
+        if entry.tombstone != 0 {
+            return Ok(());
+        }
+        entry.tombstone = 1;
+
+        let mut dict_file = OpenOptions::new().write(true).open(
+            self.source_db_root
+                .join(DICTIONARY)
+                .join(format!("{}.qsdd", dict_idx)),
+        )?;
+        dict_file.seek(SeekFrom::Start(
+            (BYTES_DICTIONARY_HEADER as u64) + (BYTES_DICTIONARY_BLOCK as u64) * position,
+        ))?;
+        entry.to_writer(&mut dict_file)?;
+        dict_file.sync_all()?;
+        Ok(())
+    }
+
+    /// Indexes the source stored as `nth` (1-based, see `Block::nth`) at
+    /// `offset` under `key`, an
+    /// order-preserving byte string built with `index::encode_key`.
+    pub fn index_insert(&self, key: &[u8], nth: u64, offset: u64) -> io::Result<()> {
+        IndexFile::open(self.source_db_root.clone()).insert(key, nth, offset)
+    }
+
+    /// Returns the `Block`s whose secondary-index key falls in `[lo, hi]`,
+    /// in key order. Loads the whole index, then binary-searches to `lo`
+    /// (see `IndexFile::range_query`).
+    pub fn range_query(&self, lo: &[u8], hi: &[u8]) -> io::Result<Vec<Block>> {
+        IndexFile::open(self.source_db_root.clone()).range_query(lo, hi)
+    }
+
+    /// Returns the `Block`s whose secondary-index key starts with `prefix`,
+    /// in key order.
+    pub fn prefix_scan(&self, prefix: &[u8]) -> io::Result<Vec<Block>> {
+        IndexFile::open(self.source_db_root.clone()).prefix_scan(prefix)
+    }
+
+    /// Walks the segment-tree dictionary down to the leaf dictionary file
+    /// that `idx` belongs to, without mutating anything. Used both by
+    /// `push_dict` and to precompute the journal entry for a push before
+    /// any file is touched.
+    fn dict_chain(&self, idx: u64) -> io::Result<u64> {
         let mut pivot: u64 = 1 << self.header.divisor_exp;
         let mut current: u64 = idx;
         let mut i = 0;
         loop {
             if current % pivot == 0 {
-                let mut dict_header = Self::inner_read_dict_header(i, self.source_db_root.clone())?;
-                dict_header.len += 1;
-                Self::inner_write_dict_header(
-                    i,
-                    self.source_db_root.clone(),
-                    dict_header,
-                    Mode::Modification,
-                )?;
-                let mut dict_file = OpenOptions::new().write(true).open(
-                    self.source_db_root
-                        .clone()
-                        .join(DICTIONARY)
-                        .join(format!("{}.qsdd", i)),
-                )?;
-                let dict_block = DictionaryBlock {
-                    nth: idx,
-                    offset: offset,
-                };
-                dict_file.seek(SeekFrom::End(0))?;
-                serialize_into(&mut dict_file, &dict_block).ok();
-                break;
+                return Ok(i);
             }
             let current_block = self.dict_get(i, current / pivot)?;
             i = current_block.nth;
             current %= pivot;
             pivot >>= 1;
         }
+    }
+
+    pub fn push_dict(&self, dict_idx: u64, idx: u64, offset: u64) -> io::Result<()> {
+        // TODO: Reduce some overhead
+        // - too many file open(s) occur
+        let mut dict_header = Self::inner_read_dict_header(dict_idx, self.source_db_root.clone())?;
+        dict_header.len += 1;
         Self::inner_write_dict_header(
-            idx,
+            dict_idx,
             self.source_db_root.clone(),
-            DictionaryHeader { len: 0 },
-            Mode::Create,
+            dict_header,
+            Mode::Modification,
+        )?;
+        let mut dict_file = OpenOptions::new().write(true).open(
+            self.source_db_root
+                .clone()
+                .join(DICTIONARY)
+                .join(format!("{}.qsdd", dict_idx)),
         )?;
+        let dict_block = DictionaryBlock {
+            nth: idx,
+            offset: offset,
+            tombstone: 0,
+        };
+        dict_file.seek(SeekFrom::End(0))?;
+        dict_block.to_writer(&mut dict_file)?;
+
+        // Only pre-create a child dictionary file when the segment tree can
+        // actually descend into it. Under the default flattened tree
+        // (`divisor_exp == 0`, see `DEFAULT_EXP`) `dict_chain` never reads a
+        // child file, so creating one here just leaks a `{idx}.qsdd` file on
+        // every single push.
+        if self.header.divisor_exp > 0 {
+            Self::inner_write_dict_header(
+                idx,
+                self.source_db_root.clone(),
+                DictionaryHeader { len: 0 },
+                Mode::Create,
+            )?;
+        }
         Ok(())
     }
+
+    /// Rewrites `sources.qsdb`, the dictionary and the secondary index,
+    /// keeping only live (non-tombstoned) blocks and discarding tombstoned
+    /// ones for good. Surviving sources keep their `nth` but get a new,
+    /// denser offset, which the rebuilt index is updated to match.
+    ///
+    /// All three new files are built fully alongside the old ones first;
+    /// only once every one of them is written and synced does `compact`
+    /// write `COMPACT_JOURNAL` and start swapping them in. A crash before
+    /// that marker exists leaves the original database completely
+    /// untouched (the staged files are simply orphaned and ignored); a
+    /// crash after it exists is finished by `DBFile::open`'s
+    /// `recover_compaction`, which redoes whichever swaps hadn't made it to
+    /// disk yet rather than leaving any of the three files out of sync with
+    /// the others.
+    pub fn compact(&mut self) -> io::Result<CompactionReport> {
+        let dict_dir = self.source_db_root.join(DICTIONARY);
+        let mut dict_indices = Vec::new();
+        for dir_entry in std::fs::read_dir(&dict_dir)? {
+            let dir_entry = dir_entry?;
+            if let Some(idx) = dir_entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".qsdd"))
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                dict_indices.push(idx);
+            }
+        }
+
+        let mut entries = Vec::new();
+        let mut dead: u64 = 0;
+        for dict_idx in &dict_indices {
+            let dict_header =
+                Self::inner_read_dict_header(*dict_idx, self.source_db_root.clone())?;
+            for i in 0..dict_header.len {
+                let entry = self.dict_get(*dict_idx, i)?;
+                if entry.tombstone != 0 {
+                    dead += 1;
+                }
+                entries.push(entry);
+            }
+        }
+
+        let old_db_path = self.source_db_root.join(MASTER_DB);
+        let old_len = old_db_path.metadata()?.len();
+
+        // Tombstoned entries still occupy space between live ones, so the
+        // body length of each block has to be derived from the next block
+        // in physical offset order, not from the (possibly discontinuous)
+        // `nth` order.
+        let mut by_offset = entries.clone();
+        by_offset.sort_by_key(|e| e.offset);
+        let mut body_lens: HashMap<u64, u64> = HashMap::new();
+        for (i, e) in by_offset.iter().enumerate() {
+            let body_start = e.offset + BYTES_BLOCK as u64;
+            let body_end = by_offset
+                .get(i + 1)
+                .map(|next| next.offset)
+                .unwrap_or(old_len);
+            body_lens.insert(e.nth, body_end - body_start);
+        }
+
+        let mut live: Vec<DictionaryBlock> =
+            entries.into_iter().filter(|e| e.tombstone == 0).collect();
+        live.sort_by_key(|e| e.nth);
+
+        let new_db_path = self.source_db_root.join(DB_STAGED);
+        let mut old_db_file = File::open(&old_db_path)?;
+        let mut new_db_file = File::create(&new_db_path)?;
+        self.header.to_writer(&mut new_db_file)?;
+
+        let mut new_offsets: HashMap<u64, u64> = HashMap::new();
+        for e in &live {
+            let body_len = body_lens[&e.nth];
+            let new_offset = new_db_file.stream_position()?;
+            old_db_file.seek(SeekFrom::Start(e.offset))?;
+            let mut buf = vec![0u8; BYTES_BLOCK + body_len as usize];
+            old_db_file.read_exact(&mut buf)?;
+            new_db_file.write_all(&buf)?;
+            new_offsets.insert(e.nth, new_offset);
+        }
+        new_db_file.sync_all()?;
+        let reclaimed_bytes = old_len.saturating_sub(new_db_file.metadata()?.len());
+
+        // Rebuild the dictionary levels from scratch in a scratch directory
+        // by replaying the same `push_dict` descent used at insertion time,
+        // this time against the new, denser offsets.
+        let rebuild_root = self.source_db_root.join("_compact_rebuild");
+        create_dir(&rebuild_root)?;
+        create_dir(rebuild_root.join(DICTIONARY))?;
+        Self::inner_write_dict_header(0, rebuild_root.clone(), DictionaryHeader { len: 0 }, Mode::Create)?;
+        let shadow = DBFile {
+            source_db_root: rebuild_root.clone(),
+            header: self.header,
+            signing_key: None,
+        };
+        for e in &live {
+            let offset = new_offsets[&e.nth];
+            let dict_idx = shadow.dict_chain(e.nth)?;
+            shadow.push_dict(dict_idx, e.nth, offset)?;
+        }
+        let dict_staged = self.source_db_root.join(DICT_STAGED);
+        std::fs::rename(rebuild_root.join(DICTIONARY), &dict_staged)?;
+        std::fs::remove_dir_all(&rebuild_root)?;
+
+        let index_staged = self.source_db_root.join(INDEX_STAGED);
+        IndexFile::open(self.source_db_root.clone()).compact_to(&new_offsets, &index_staged)?;
+
+        // Every staged file (dictionary, sources, index) is now fully
+        // written and synced. This marker commits to swapping all three in;
+        // a crash anywhere past this point is finished by
+        // `recover_compaction`, never rolled back, since the swaps below
+        // may already have started moving the live files aside.
+        let journal = File::create(self.source_db_root.join(COMPACT_JOURNAL))?;
+        journal.sync_all()?;
+        drop(journal);
+
+        Self::swap_in_dir(
+            &dict_dir,
+            &dict_staged,
+            &self.source_db_root.join(DICT_BACKUP),
+        )?;
+        Self::swap_in_file(
+            &old_db_path,
+            &new_db_path,
+            &self.source_db_root.join(DB_BACKUP),
+        )?;
+        Self::swap_in_file(
+            &self.source_db_root.join(INDEX_FILE),
+            &index_staged,
+            &self.source_db_root.join(INDEX_BACKUP),
+        )?;
+
+        std::fs::remove_file(self.source_db_root.join(COMPACT_JOURNAL))?;
+
+        Ok(CompactionReport {
+            live: live.len() as u64,
+            dead,
+            reclaimed_bytes,
+        })
+    }
+}
+
+// White-box tests for the journal/recovery protocol, which `lib.rs`'s tests
+// can't exercise directly since hand-simulating a mid-commit crash needs
+// `JournalEntry`/`write_journal`, neither of which is part of the public API.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::from_utf8;
+
+    #[test]
+    fn recovers_from_interrupted_push() {
+        let root: PathBuf = ["./test_recovers_from_interrupted_push"].iter().collect();
+        std::fs::create_dir_all(&root).unwrap();
+        DBFile::new(root.clone(), None, None).unwrap();
+        let mut f = DBFile::open(root.clone(), None).unwrap();
+        f.push(b"first", Codec::None).unwrap();
+
+        // Hand-simulate the state `push` leaves behind after journaling its
+        // intent but before the block's body lands in `sources.qsdb`: bump
+        // the header/dictionary the same way `push` does, journal a matching
+        // entry, then truncate the body away to stand in for the crash.
+        let header_len_before = f.header.len;
+        let nth = header_len_before + 1;
+        let offset = File::open(root.join(MASTER_DB))
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .len();
+        let dict_idx = f.dict_chain(nth).unwrap();
+        let dict_len_before = File::open(
+            root.join(DICTIONARY).join(format!("{}.qsdd", dict_idx)),
+        )
+        .unwrap()
+        .metadata()
+        .unwrap()
+        .len();
+
+        DBFile::write_journal(
+            root.clone(),
+            &JournalEntry {
+                nth,
+                offset,
+                body_len: 5,
+                header_len_before,
+                dict_idx,
+                dict_len_before,
+            },
+        )
+        .unwrap();
+
+        let mut header = f.header;
+        header.len = nth;
+        DBFile::inner_write_header(root.clone(), header, Mode::Modification).unwrap();
+        f.push_dict(dict_idx, nth, offset).unwrap();
+
+        // The block/body write never happened: leave `sources.qsdb` exactly
+        // as long as it was before this push began.
+        let db_file = OpenOptions::new()
+            .write(true)
+            .open(root.join(MASTER_DB))
+            .unwrap();
+        db_file.set_len(offset).unwrap();
+        db_file.sync_all().unwrap();
+        drop(db_file);
+
+        // Reopening should roll the header and dictionary back to how they
+        // were before the interrupted push, not leave them pointing past the
+        // truncated body.
+        let recovered = DBFile::open(root.clone(), None).unwrap();
+        assert_eq!(recovered.header.len, header_len_before);
+        assert!(!root.join(JOURNAL).exists());
+        assert_eq!(
+            from_utf8(&recovered.get(0, false).unwrap()).unwrap(),
+            "first"
+        );
+    }
+
+    #[test]
+    fn replays_a_push_whose_body_already_landed() {
+        let root: PathBuf = ["./test_replays_a_push_whose_body_already_landed"]
+            .iter()
+            .collect();
+        std::fs::create_dir_all(&root).unwrap();
+        DBFile::new(root.clone(), None, None).unwrap();
+        let mut f = DBFile::open(root.clone(), None).unwrap();
+        f.push(b"first", Codec::None).unwrap();
+        let header_len_before = f.header.len;
+
+        // Simulate a crash *after* the body was fully written but before the
+        // journal was cleared, by re-journaling the just-committed push
+        // without touching the header or `sources.qsdb` at all.
+        let nth = header_len_before;
+        let offset = f.locate(nth).unwrap();
+        let dict_idx = f.dict_chain(nth).unwrap();
+        let body_len = File::open(root.join(MASTER_DB))
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .len()
+            - offset
+            - BYTES_BLOCK as u64;
+
+        DBFile::write_journal(
+            root.clone(),
+            &JournalEntry {
+                nth,
+                offset,
+                body_len,
+                header_len_before: header_len_before - 1,
+                dict_idx,
+                dict_len_before: BYTES_DICTIONARY_HEADER as u64,
+            },
+        )
+        .unwrap();
+
+        // Recovery should see the body already landed and just make sure the
+        // header agrees with it, rather than rolling a completed push back.
+        let recovered = DBFile::open(root.clone(), None).unwrap();
+        assert_eq!(recovered.header.len, header_len_before);
+        assert!(!root.join(JOURNAL).exists());
+        assert_eq!(
+            from_utf8(&recovered.get(0, false).unwrap()).unwrap(),
+            "first"
+        );
+    }
+
+    #[test]
+    fn recovers_a_compact_interrupted_after_the_marker() {
+        let root: PathBuf = ["./test_recovers_a_compact_interrupted_after_the_marker"]
+            .iter()
+            .collect();
+        std::fs::create_dir_all(&root).unwrap();
+        DBFile::new(root.clone(), None, None).unwrap();
+        let mut f = DBFile::open(root.clone(), None).unwrap();
+        f.push(b"one", Codec::None).unwrap();
+        f.push(b"two", Codec::None).unwrap();
+        f.delete(0).unwrap();
+
+        // `compact` always runs every swap to completion in-process, so to
+        // exercise the crash path we reproduce the mid-swap filesystem
+        // state by hand afterwards and confirm the next `DBFile::open`
+        // finishes it.
+        let report = f.compact().unwrap();
+        assert_eq!(report.live, 1);
+
+        // Hand-simulate the state a crash leaves behind right after
+        // `compact` writes `COMPACT_JOURNAL` but before any of the three
+        // swaps land: `compact` only ever writes that marker once all three
+        // staged files exist, so reproduce that by staging copies of the
+        // (already-compacted) live dictionary, sources and index files.
+        let dict_dir = root.join(DICTIONARY);
+        let dict_staged = root.join(DICT_STAGED);
+        dict_copy(&dict_dir, &dict_staged);
+        let db_staged = root.join(DB_STAGED);
+        std::fs::copy(root.join(MASTER_DB), &db_staged).unwrap();
+        let index_staged = root.join(INDEX_STAGED);
+        std::fs::copy(root.join(INDEX_FILE), &index_staged).unwrap();
+        File::create(root.join(COMPACT_JOURNAL))
+            .unwrap()
+            .sync_all()
+            .unwrap();
+
+        // Reopening should finish all three interrupted swaps rather than
+        // leaving the journal marker or any staged/backup files behind.
+        DBFile::open(root.clone(), None).unwrap();
+        assert!(!root.join(COMPACT_JOURNAL).exists());
+        assert!(!dict_staged.exists());
+        assert!(!db_staged.exists());
+        assert!(!index_staged.exists());
+        assert!(!root.join(DICT_BACKUP).exists());
+        assert!(!root.join(DB_BACKUP).exists());
+        assert!(!root.join(INDEX_BACKUP).exists());
+        assert!(dict_dir.exists());
+        assert!(root.join(MASTER_DB).exists());
+        assert!(root.join(INDEX_FILE).exists());
+    }
+
+    /// Recursively copies a directory tree, since `std::fs` has no built-in
+    /// equivalent -- used to stage a throwaway copy of the dictionary for
+    /// `recovers_a_compact_interrupted_after_the_marker`.
+    fn dict_copy(src: &Path, dst: &Path) {
+        create_dir(dst).unwrap();
+        for entry in std::fs::read_dir(src).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), dst.join(entry.file_name())).unwrap();
+        }
+    }
 }
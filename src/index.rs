@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+
+use crate::db::{Block, FromReader, MASTER_DB};
+
+pub const INDEX_FILE: &'static str = "index.qsxi";
+pub const BYTES_INDEX_HEADER: usize = 8;
+
+// Type tags for the order-preserving key encoding. Each component is
+// prefixed by its tag so raw byte comparison of two encoded keys reproduces
+// the logical ordering of the key tuples they were built from.
+pub const TAG_NULL: u8 = 0x01;
+pub const TAG_FALSE: u8 = 0x02;
+pub const TAG_TRUE: u8 = 0x03;
+pub const TAG_NUMBER: u8 = 0x05;
+pub const TAG_STRING: u8 = 0x06;
+pub const TAG_BYTES: u8 = 0x07;
+
+/// One component of a secondary-index key. Encoded components are
+/// concatenated so the whole key is memcmp-orderable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyPart {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl KeyPart {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            KeyPart::Null => out.push(TAG_NULL),
+            KeyPart::Bool(false) => out.push(TAG_FALSE),
+            KeyPart::Bool(true) => out.push(TAG_TRUE),
+            KeyPart::Number(n) => {
+                out.push(TAG_NUMBER);
+                // Two's-complement integers become unsigned-comparable by
+                // flipping the sign bit: this pushes negatives below
+                // positives while keeping big-endian byte order == numeric
+                // order within each half.
+                let flipped = (*n as u64) ^ (1u64 << 63);
+                out.extend_from_slice(&flipped.to_be_bytes());
+            }
+            KeyPart::String(s) => {
+                out.push(TAG_STRING);
+                encode_bytes_component(s.as_bytes(), out);
+            }
+            KeyPart::Bytes(b) => {
+                out.push(TAG_BYTES);
+                encode_bytes_component(b, out);
+            }
+        }
+    }
+}
+
+// 0x00 is reserved as the component terminator, so escape any literal 0x00
+// byte as 0x00 0xFF before terminating with 0x00 0x00. This keeps a key
+// that is a byte-wise prefix of another sorting below it.
+fn encode_bytes_component(data: &[u8], out: &mut Vec<u8>) {
+    for &b in data {
+        if b == 0x00 {
+            out.push(0x00);
+            out.push(0xFF);
+        } else {
+            out.push(b);
+        }
+    }
+    out.push(0x00);
+    out.push(0x00);
+}
+
+/// Encodes a tuple of `KeyPart`s into a single memcmp-orderable byte string.
+pub fn encode_key(parts: &[KeyPart]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for part in parts {
+        part.encode(&mut out);
+    }
+    out
+}
+
+#[derive(Clone, Debug)]
+struct IndexEntry {
+    key: Vec<u8>,
+    nth: u64,
+    offset: u64,
+}
+
+/// Sorted secondary index mapping an application-chosen, order-preserving
+/// key to the `nth`/offset of a source already tracked by the dictionary.
+///
+/// The backing file lives alongside `sources.qsdb` and the `dictionary`
+/// directory, as `index.qsxi`.
+pub struct IndexFile {
+    path: PathBuf,
+}
+
+impl IndexFile {
+    pub fn create(source_db_root: PathBuf) -> io::Result<Self> {
+        let path = source_db_root.join(INDEX_FILE);
+        let mut f = File::create(&path)?;
+        f.write_all(&0u64.to_be_bytes())?;
+        f.sync_all()?;
+        Ok(Self { path })
+    }
+
+    pub fn open(source_db_root: PathBuf) -> Self {
+        Self {
+            path: source_db_root.join(INDEX_FILE),
+        }
+    }
+
+    fn read_all(&self) -> io::Result<Vec<IndexEntry>> {
+        let mut f = File::open(&self.path)?;
+        let mut header_buf: [u8; BYTES_INDEX_HEADER] = [0; BYTES_INDEX_HEADER];
+        f.read_exact(&mut header_buf)?;
+        let count = u64::from_be_bytes(header_buf);
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut len_buf = [0u8; 4];
+            f.read_exact(&mut len_buf)?;
+            let key_len = u32::from_be_bytes(len_buf) as usize;
+            let mut key = vec![0u8; key_len];
+            f.read_exact(&mut key)?;
+            let mut nth_buf = [0u8; 8];
+            f.read_exact(&mut nth_buf)?;
+            let mut offset_buf = [0u8; 8];
+            f.read_exact(&mut offset_buf)?;
+            entries.push(IndexEntry {
+                key,
+                nth: u64::from_be_bytes(nth_buf),
+                offset: u64::from_be_bytes(offset_buf),
+            });
+        }
+        Ok(entries)
+    }
+
+    // TODO: Reduce some overhead - this rewrites the whole file on every
+    // insert instead of splicing the new entry in place.
+    fn write_all(&self, entries: &[IndexEntry]) -> io::Result<()> {
+        let mut f = File::create(&self.path)?;
+        f.write_all(&(entries.len() as u64).to_be_bytes())?;
+        for e in entries {
+            f.write_all(&(e.key.len() as u32).to_be_bytes())?;
+            f.write_all(&e.key)?;
+            f.write_all(&e.nth.to_be_bytes())?;
+            f.write_all(&e.offset.to_be_bytes())?;
+        }
+        f.sync_all()?;
+        Ok(())
+    }
+
+    /// Inserts `key -> nth` (at the `offset` the dictionary already tracks
+    /// for it), keeping the backing file sorted by raw key bytes so range
+    /// and prefix scans can stop as soon as they leave the matching run.
+    pub fn insert(&self, key: &[u8], nth: u64, offset: u64) -> io::Result<()> {
+        let mut entries = self.read_all()?;
+        let pos = entries.partition_point(|e| e.key.as_slice() < key);
+        entries.insert(
+            pos,
+            IndexEntry {
+                key: key.to_vec(),
+                nth,
+                offset,
+            },
+        );
+        self.write_all(&entries)
+    }
+
+    /// Returns the `Block`s whose keys fall in `[lo, hi]`, in key order.
+    /// Entries are kept sorted by `insert`, so this can jump straight to
+    /// `lo` with a binary search and stop as soon as it passes `hi`,
+    /// instead of scanning every entry in the index.
+    pub fn range_query(&self, lo: &[u8], hi: &[u8]) -> io::Result<Vec<Block>> {
+        let entries = self.read_all()?;
+        let start = entries.partition_point(|e| e.key.as_slice() < lo);
+        let mut out = Vec::new();
+        for e in &entries[start..] {
+            if e.key.as_slice() > hi {
+                break;
+            }
+            out.push(self.read_block(e.offset)?);
+        }
+        Ok(out)
+    }
+
+    /// Returns the `Block`s whose keys start with `prefix`, in key order.
+    /// Entries matching `prefix` form a contiguous run in the sorted file,
+    /// so this jumps to its start with a binary search and stops as soon as
+    /// it leaves the run.
+    pub fn prefix_scan(&self, prefix: &[u8]) -> io::Result<Vec<Block>> {
+        let entries = self.read_all()?;
+        let start = entries.partition_point(|e| e.key.as_slice() < prefix);
+        let mut out = Vec::new();
+        for e in &entries[start..] {
+            if !e.key.starts_with(prefix) {
+                break;
+            }
+            out.push(self.read_block(e.offset)?);
+        }
+        Ok(out)
+    }
+
+    /// Rewrites the index to match a `DBFile::compact` pass -- entries whose
+    /// `nth` no longer appears in `new_offsets` were tombstoned away and are
+    /// dropped, the rest have their `offset` updated to where `compact`
+    /// relocated their block -- and writes the result to `dest_path` rather
+    /// than the live index file, so the caller can stage it and swap it in
+    /// atomically alongside the dictionary and sources files it was derived
+    /// from.
+    pub fn compact_to(&self, new_offsets: &HashMap<u64, u64>, dest_path: &std::path::Path) -> io::Result<()> {
+        let entries = self.read_all()?;
+        let mut kept = Vec::with_capacity(entries.len());
+        for mut e in entries {
+            if let Some(&offset) = new_offsets.get(&e.nth) {
+                e.offset = offset;
+                kept.push(e);
+            }
+        }
+        Self {
+            path: dest_path.to_path_buf(),
+        }
+        .write_all(&kept)
+    }
+
+    fn read_block(&self, offset: u64) -> io::Result<Block> {
+        let root = self.path.parent().unwrap();
+        let mut db_file = File::open(root.join(MASTER_DB))?;
+        db_file.seek(SeekFrom::Start(offset))?;
+        Block::from_reader(&mut db_file)
+    }
+}
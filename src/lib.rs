@@ -1,30 +1,277 @@
 #![feature(fixed_size_array)]
 
-extern crate bincode;
 extern crate compress;
-extern crate serde;
+extern crate secp256k1;
+extern crate sha2;
 
 mod db;
+mod index;
 
 #[cfg(test)]
 mod tests {
     #[test]
     fn it_works() {
-        use crate::db::{DBFile, DEFAULT_HEADER};
+        use crate::db::{Codec, DBFile, DEFAULT_HEADER};
         use std::str::from_utf8;
-        DBFile::new(["./test"].iter().collect(), None).unwrap();
-        let mut f = DBFile::open(["./test"].iter().collect()).unwrap();
+        DBFile::new(["./test"].iter().collect(), None, None).unwrap();
+        let mut f = DBFile::open(["./test"].iter().collect(), None).unwrap();
         assert_eq!(f.header(), DEFAULT_HEADER);
-        f.push("An efficient database for storing code(s)".as_bytes(), true)
-            .ok();
-        f.push("Enumerative combinatorics".as_bytes(), true).ok();
-        f.push("Algebra".as_bytes(), true).ok();
-        f.push("Discrete mathematics".as_bytes(), true).ok();
-        dbg!(DBFile::inner_read_dict_header(f.path()).unwrap());
+        f.push(
+            "An efficient database for storing code(s)".as_bytes(),
+            Codec::RleArithmetic,
+        )
+        .ok();
+        f.push(
+            "Enumerative combinatorics".as_bytes(),
+            Codec::RleArithmetic,
+        )
+        .ok();
+        f.push("Algebra".as_bytes(), Codec::RleArithmetic).ok();
+        f.push(
+            "Discrete mathematics".as_bytes(),
+            Codec::RleArithmetic,
+        )
+        .ok();
+        dbg!(DBFile::inner_read_dict_header(0, f.path()).unwrap());
         assert_eq!(
             from_utf8(&f.get(0, true).unwrap()).unwrap(),
             "An efficient database for storing code(s)"
         );
         assert_ne!(DBFile::inner_read_header(f.path()).unwrap(), DEFAULT_HEADER);
     }
+
+    #[test]
+    fn get_uncompressed() {
+        use crate::db::{Codec, DBFile};
+        use std::str::from_utf8;
+        let sources = ["Graph theory", "Number theory", "Topology", "Set theory"];
+        DBFile::new(["./test_get_uncompressed"].iter().collect(), None, None).unwrap();
+        let mut f = DBFile::open(["./test_get_uncompressed"].iter().collect(), None).unwrap();
+        for source in &sources {
+            f.push(source.as_bytes(), Codec::None).unwrap();
+        }
+        for (nth, source) in sources.iter().enumerate() {
+            assert_eq!(
+                from_utf8(&f.get(nth as u64, false).unwrap()).unwrap(),
+                *source
+            );
+        }
+    }
+
+    #[test]
+    fn get_compressed() {
+        use crate::db::{Codec, DBFile};
+        use std::str::from_utf8;
+        let sources = [
+            "Probability theory",
+            "Linear algebra",
+            "Category theory",
+            "Numerical analysis",
+        ];
+        DBFile::new(["./test_get_compressed"].iter().collect(), None, None).unwrap();
+        let mut f = DBFile::open(["./test_get_compressed"].iter().collect(), None).unwrap();
+        for source in &sources {
+            f.push(source.as_bytes(), Codec::RleArithmetic).unwrap();
+        }
+        for (nth, source) in sources.iter().enumerate() {
+            assert_eq!(
+                from_utf8(&f.get(nth as u64, true).unwrap()).unwrap(),
+                *source
+            );
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        use crate::db::{Codec, DBFile};
+        use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let other_key = SecretKey::from_slice(&[9u8; 32]).unwrap();
+
+        DBFile::new(
+            ["./test_sign_and_verify"].iter().collect(),
+            None,
+            Some(secret_key),
+        )
+        .unwrap();
+        let mut f = DBFile::open(
+            ["./test_sign_and_verify"].iter().collect(),
+            Some(secret_key),
+        )
+        .unwrap();
+        f.push("Submission for problem 42".as_bytes(), Codec::RleArithmetic)
+            .unwrap();
+
+        assert!(f.verify(0).unwrap());
+        assert!(f
+            .verify_public(0, &PublicKey::from_secret_key(&secp, &secret_key))
+            .unwrap());
+        assert!(!f
+            .verify_public(0, &PublicKey::from_secret_key(&secp, &other_key))
+            .unwrap());
+        let address = DBFile::address(&PublicKey::from_secret_key(&secp, &secret_key));
+        assert!(f.verify_address(0, &address).unwrap());
+    }
+
+    #[test]
+    fn delete_and_compact() {
+        use crate::db::{Codec, DBFile};
+        use std::str::from_utf8;
+        let sources = ["Combinatorics", "Topology", "Graph theory", "Set theory"];
+        DBFile::new(["./test_delete_and_compact"].iter().collect(), None, None).unwrap();
+        let mut f = DBFile::open(["./test_delete_and_compact"].iter().collect(), None).unwrap();
+        for source in &sources {
+            f.push(source.as_bytes(), Codec::None).unwrap();
+        }
+
+        f.delete(1).unwrap();
+        assert!(f.get(1, false).is_err());
+        assert_eq!(from_utf8(&f.get(0, false).unwrap()).unwrap(), sources[0]);
+
+        let report = f.compact().unwrap();
+        assert_eq!(report.live, 3);
+        assert_eq!(report.dead, 1);
+        assert!(report.reclaimed_bytes > 0);
+
+        assert!(f.get(1, false).is_err());
+        assert_eq!(from_utf8(&f.get(0, false).unwrap()).unwrap(), sources[0]);
+        assert_eq!(from_utf8(&f.get(2, false).unwrap()).unwrap(), sources[2]);
+        assert_eq!(from_utf8(&f.get(3, false).unwrap()).unwrap(), sources[3]);
+    }
+
+    #[test]
+    fn index_key_ordering_and_scans() {
+        use crate::db::{Codec, DBFile, MASTER_DB};
+        use crate::index::{encode_key, KeyPart};
+
+        // Negative numbers must sort below positive ones, and a key that is
+        // a byte-wise prefix of another must still sort below it.
+        assert!(encode_key(&[KeyPart::Number(-5)]) < encode_key(&[KeyPart::Number(0)]));
+        assert!(encode_key(&[KeyPart::Number(0)]) < encode_key(&[KeyPart::Number(5)]));
+        assert!(
+            encode_key(&[KeyPart::String("ab".to_string())])
+                < encode_key(&[KeyPart::String("abc".to_string())])
+        );
+        assert!(encode_key(&[KeyPart::Bool(false)]) < encode_key(&[KeyPart::Bool(true)]));
+
+        let root: std::path::PathBuf = ["./test_index_key_ordering_and_scans"].iter().collect();
+        DBFile::new(root.clone(), None, None).unwrap();
+        let mut f = DBFile::open(root.clone(), None).unwrap();
+
+        let sources = ["alpha", "bravo", "charlie"];
+        let db_path = root.join(MASTER_DB);
+        let mut offsets = Vec::new();
+        for (i, source) in sources.iter().enumerate() {
+            let offset = std::fs::metadata(&db_path).unwrap().len();
+            f.push(source.as_bytes(), Codec::None).unwrap();
+            offsets.push(offset);
+            // `index_insert`'s `nth` is the real 1-based insertion ordinal
+            // (`Block::nth()`), not the loop counter -- `compact` remaps
+            // entries by looking that exact value up in its offsets map.
+            // Inserted out of order to exercise `insert`'s sorted placement.
+            f.index_insert(
+                &encode_key(&[KeyPart::Number(2 - i as i64)]),
+                (i + 1) as u64,
+                offset,
+            )
+            .unwrap();
+        }
+
+        // "charlie" (pushed 3rd, so `Block::nth() == 3`) was indexed under
+        // key 0, "alpha" (`nth() == 1`) under key 2, so a range query over
+        // the low key should surface "charlie" first.
+        let hits = f
+            .range_query(
+                &encode_key(&[KeyPart::Number(0)]),
+                &encode_key(&[KeyPart::Number(1)]),
+            )
+            .unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].nth(), 3);
+        assert_eq!(hits[1].nth(), 2);
+
+        let all = f
+            .range_query(
+                &encode_key(&[KeyPart::Number(0)]),
+                &encode_key(&[KeyPart::Number(2)]),
+            )
+            .unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].nth(), 1);
+
+        // Also index "alpha" and "bravo" under a shared string prefix, to
+        // exercise prefix_scan's early exit once it leaves the matching run.
+        f.index_insert(
+            &encode_key(&[KeyPart::String("lang/alpha".to_string())]),
+            1,
+            offsets[0],
+        )
+        .unwrap();
+        f.index_insert(
+            &encode_key(&[KeyPart::String("lang/bravo".to_string())]),
+            2,
+            offsets[1],
+        )
+        .unwrap();
+        f.index_insert(
+            &encode_key(&[KeyPart::String("other/charlie".to_string())]),
+            3,
+            offsets[2],
+        )
+        .unwrap();
+        let mut prefix = vec![crate::index::TAG_STRING];
+        prefix.extend_from_slice(b"lang/");
+        let prefixed = f.prefix_scan(&prefix).unwrap();
+        assert_eq!(prefixed.len(), 2);
+    }
+
+    #[test]
+    fn index_entries_survive_compact() {
+        use crate::db::{Codec, DBFile, MASTER_DB};
+        use crate::index::{encode_key, KeyPart};
+
+        let root: std::path::PathBuf = ["./test_index_entries_survive_compact"].iter().collect();
+        DBFile::new(root.clone(), None, None).unwrap();
+        let mut f = DBFile::open(root.clone(), None).unwrap();
+
+        let sources = ["alpha", "bravo", "charlie"];
+        let db_path = root.join(MASTER_DB);
+        let mut offsets = Vec::new();
+        for source in &sources {
+            let offset = std::fs::metadata(&db_path).unwrap().len();
+            f.push(source.as_bytes(), Codec::None).unwrap();
+            offsets.push(offset);
+        }
+        for (i, source) in sources.iter().enumerate() {
+            f.index_insert(
+                &encode_key(&[KeyPart::String(source.to_string())]),
+                (i + 1) as u64,
+                offsets[i],
+            )
+            .unwrap();
+        }
+
+        // Drop "alpha" (`Block::nth() == 1`) and compact: its index entry
+        // should vanish along with its tombstoned block, while "bravo" and
+        // "charlie" stay findable at whatever offset `compact` relocated
+        // them to.
+        f.delete(0).unwrap();
+        f.compact().unwrap();
+
+        let alpha_key = encode_key(&[KeyPart::String("alpha".to_string())]);
+        let alpha_hits = f.range_query(&alpha_key, &alpha_key).unwrap();
+        assert_eq!(alpha_hits.len(), 0);
+
+        let bravo_key = encode_key(&[KeyPart::String("bravo".to_string())]);
+        let bravo_hits = f.range_query(&bravo_key, &bravo_key).unwrap();
+        assert_eq!(bravo_hits.len(), 1);
+        assert_eq!(bravo_hits[0].nth(), 2);
+
+        let charlie_key = encode_key(&[KeyPart::String("charlie".to_string())]);
+        let charlie_hits = f.range_query(&charlie_key, &charlie_key).unwrap();
+        assert_eq!(charlie_hits.len(), 1);
+        assert_eq!(charlie_hits[0].nth(), 3);
+    }
 }